@@ -3,88 +3,440 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
 use heapless::spsc::Queue;
+use heapless::Vec;
 use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
-use stm32l4::stm32l4x2::{interrupt, Interrupt, Peripherals, SPI1, USART2};
+use serde::{Deserialize, Serialize};
+use stm32l4::stm32l4x2::{interrupt, Interrupt, Peripherals, IWDG, SPI1, USART2};
 
-static mut USART2_PERIPHERAL: Option<USART2> = None;
-static mut SPI1_PERIPHERAL: Option<SPI1> = None;
+static USART2_PERIPHERAL: Mutex<RefCell<Option<USART2>>> = Mutex::new(RefCell::new(None));
+static SPI1_PERIPHERAL: Mutex<RefCell<Option<SPI1>>> = Mutex::new(RefCell::new(None));
+static IWDG_PERIPHERAL: Mutex<RefCell<Option<IWDG>>> = Mutex::new(RefCell::new(None));
+
+/// Enables the independent watchdog, which resets the device if the `SPI1` ISR's
+/// `while spi1.sr.read().bsy().bit_is_set() {}` busy-wait ever wedges. Off by default: this
+/// example runs under `panic_semihosting` and a halted debugger, and nothing kicks the
+/// watchdog while execution is stopped at a breakpoint, so it would reset out from under a
+/// debug session. Only enable once `DBGMCU` is configured to freeze IWDG on halt.
+const WITH_WDT: bool = false;
+/// IWDG prescaler divider selector written to `IWDG.pr`: 6 selects /256.
+const IWDG_PRESCALER: u8 = 6;
+/// IWDG reload value (12-bit). With the /256 prescaler programmed into `IWDG.pr` below and a
+/// ~32kHz LSI, `(IWDG_RELOAD + 1) * 256 / 32000` is about a 2 second timeout.
+const IWDG_RELOAD: u16 = 250;
+
+/// Reload the IWDG counter. Call from the main loop and after each serviced SPI1 transfer.
+fn kick_iwdg(iwdg: &IWDG) {
+    iwdg.kr.write(|w| w.key().bits(0xAAAA));
+}
 /// Bytes to be transmitted over SPI1
-static mut TX_BUFFER: Option<Queue<u16, 16>> = None;
+static TX_BUFFER: Mutex<RefCell<Option<Queue<u16, 16>>>> = Mutex::new(RefCell::new(None));
 /// Bytes received over SPI1
-static mut RX_BUFFER: Option<Queue<u16, 16>> = None;
+static RX_BUFFER: Mutex<RefCell<Option<Queue<u16, 16>>>> = Mutex::new(RefCell::new(None));
+
+/// Maximum length of one line of the JSON config protocol. Ordinary (non-command) echo traffic
+/// pays for this choice too: every byte is held in `LINE_BUFFER` until a `\n` arrives, so a run
+/// of up to `LINE_LEN` newline-free bytes is batched before reaching SPI1 instead of forwarded
+/// byte-by-byte as it used to be.
+const LINE_LEN: usize = 64;
+/// Accumulates bytes from the USART2 RX path until a newline-terminated config line is seen.
+static LINE_BUFFER: Mutex<RefCell<Vec<u8, LINE_LEN>>> = Mutex::new(RefCell::new(Vec::new()));
+
+/// Runtime-configurable SPI1 parameters, applied between transfers over the USART2 link.
+#[derive(Deserialize, Serialize)]
+struct Config {
+    spi_baud_div: u8,
+    frame_bits: u8,
+    cpol: bool,
+    cpha: bool,
+}
+
+/// Enqueue `byte` onto `tx_buffer` for SPI1 and arm the TXE interrupt, mirroring the plain
+/// USART2-RX-to-SPI1 forwarding path.
+fn forward_byte(spi1: &SPI1, tx_buffer: &mut Queue<u16, 16>, byte: u16) {
+    if tx_buffer.enqueue(byte).is_ok() {
+        spi1.cr2.modify(|_, w| w.txeie().set_bit());
+        spi1.cr1.modify(|_, w| w.spe().enabled());
+    }
+}
+
+/// Max spins to wait for USART2 TXE while writing one byte of a config reply. The reply is
+/// written from inside USART2()'s critical section, so an unbounded wait here would mask global
+/// interrupts for as long as the host leaves USART2 TX undrained; past this many spins the rest
+/// of the reply is dropped instead.
+const USART2_TXE_SPIN_LIMIT: u32 = 100_000;
+
+/// Write `byte` to `usart2.tdr` once TXE sets, giving up after `USART2_TXE_SPIN_LIMIT` spins.
+/// Returns `false` if TXE never set, meaning `byte` was dropped.
+fn write_usart2_byte(usart2: &USART2, byte: u8) -> bool {
+    let mut spins = 0;
+    while usart2.isr.read().txe().bit_is_clear() {
+        spins += 1;
+        if spins >= USART2_TXE_SPIN_LIMIT {
+            return false;
+        }
+    }
+    usart2.tdr.write(|w| w.tdr().bits(byte as u16));
+    true
+}
+
+/// Rewrite SPI1.cr1/cr2 with `config`. Only safe to call when both queues are empty.
+fn apply_config(spi1: &SPI1, config: &Config) {
+    spi1.cr1.modify(|_, w| w.spe().disabled());
+    spi1.cr1.modify(|_, w| unsafe {
+        w.br()
+            .bits(config.spi_baud_div)
+            .cpol()
+            .bit(config.cpol)
+            .cpha()
+            .bit(config.cpha)
+    });
+    spi1.cr2.modify(|_, w| unsafe { w.ds().bits(config.frame_bits) });
+}
+
+/// Selects between per-byte interrupt servicing and block transfers driven by DMA1.
+enum TransferMode {
+    Interrupt,
+    Dma,
+}
+
+/// Transfer mode selected at init; both paths remain independently testable for raw byte
+/// forwarding and SPI1 fault detection (`errie`/`read_sr`/`handle_spi_error` below run
+/// regardless of `MODE`). The JSON config protocol is the one exception: `LINE_BUFFER`
+/// accumulation only happens on USART2's RXNE path, which `Dma` mode leaves unserviced (RX is
+/// owned by `DMA1_CH6` instead), so a `Config` line sent while `MODE = TransferMode::Dma` is
+/// just forwarded through as ordinary echo bytes rather than applied.
+const MODE: TransferMode = TransferMode::Interrupt;
+
+/// Number of samples in one half of each circular DMA buffer. Must stay at or below
+/// `Queue<u16, 16>`'s usable capacity of 15, or a full half-buffer shuffle drops a sample.
+const DMA_HALF_LEN: usize = 15;
+
+/// Filled by DMA1 from `USART2.rdr`; shuffled into `tx_buffer` on each half/transfer-complete.
+static mut USART_RX_DMA: [u16; DMA_HALF_LEN * 2] = [0; DMA_HALF_LEN * 2];
+/// Drained by DMA1 into `SPI1.dr`; refilled from `tx_buffer` on each half/transfer-complete.
+static mut SPI_TX_DMA: [u16; DMA_HALF_LEN * 2] = [0; DMA_HALF_LEN * 2];
+/// Filled by DMA1 from `SPI1.dr`; shuffled into `rx_buffer` on each half/transfer-complete.
+static mut SPI_RX_DMA: [u16; DMA_HALF_LEN * 2] = [0; DMA_HALF_LEN * 2];
+/// Drained by DMA1 into `USART2.tdr`; refilled from `rx_buffer` on each half/transfer-complete.
+static mut USART_TX_DMA: [u16; DMA_HALF_LEN * 2] = [0; DMA_HALF_LEN * 2];
+
+/// SPI1 hardware CRC checking (`cr1.crcen`/`crcpr`/`cr1.crcnext`) is not wired up on this side:
+/// this is a free-running master that streams bytes over DMA with no frame boundary, so there's
+/// no point at which `CRCNEXT` could mark "last byte of a frame". `Error::Crc`/`sr.crcerr` below
+/// stay in place for symmetry with the peripheral's error handling, but `crcen` is never set, so
+/// `crcerr` can never actually fire. See `peripheral`'s `WITH_CRC`, which has a real frame
+/// boundary to anchor to via `WITH_NSS_FRAMING`.
+
+/// SPI1 fault conditions distinguished from a successful transfer.
+#[derive(Clone, Copy)]
+enum Error {
+    Overrun,
+    ModeFault,
+    Crc,
+}
+
+/// Counts of each `Error` kind observed, for a debugger/semihosting log to inspect.
+#[derive(Default, Clone, Copy)]
+struct ErrorCounters {
+    overrun: u32,
+    mode_fault: u32,
+    crc: u32,
+}
+
+static ERROR_COUNTERS: Mutex<RefCell<ErrorCounters>> = Mutex::new(RefCell::new(ErrorCounters {
+    overrun: 0,
+    mode_fault: 0,
+    crc: 0,
+}));
+
+/// Read SPI1.sr and classify a fault, modeled on embassy's `read_sr`.
+fn read_sr(spi1: &SPI1) -> Result<(), Error> {
+    let sr = spi1.sr.read();
+    if sr.ovr().bit_is_set() {
+        Err(Error::Overrun)
+    } else if sr.modf().bit_is_set() {
+        Err(Error::ModeFault)
+    } else if sr.crcerr().bit_is_set() {
+        Err(Error::Crc)
+    } else {
+        Ok(())
+    }
+}
+
+/// Clear the condition reported by `read_sr`, flush both queues to a known empty state, and
+/// record the error in `ERROR_COUNTERS`.
+fn handle_spi_error(
+    spi1: &SPI1,
+    error: Error,
+    tx_buffer: &mut Queue<u16, 16>,
+    rx_buffer: &mut Queue<u16, 16>,
+) {
+    match error {
+        Error::Overrun => {
+            // RM0394: clear OVR by reading DR then SR
+            let _ = spi1.dr.read();
+            let _ = spi1.sr.read();
+        }
+        Error::ModeFault => {
+            // RM0394: clear MODF by reading SR then writing CR1
+            let _ = spi1.sr.read();
+            spi1.cr1.modify(|_, w| w);
+        }
+        Error::Crc => spi1.sr.modify(|_, w| w.crcerr().clear_bit()),
+    }
+
+    while tx_buffer.dequeue().is_some() {}
+    while rx_buffer.dequeue().is_some() {}
+
+    cortex_m::interrupt::free(|cs| {
+        let mut counters = ERROR_COUNTERS.borrow(cs).borrow_mut();
+        match error {
+            Error::Overrun => counters.overrun += 1,
+            Error::ModeFault => counters.mode_fault += 1,
+            Error::Crc => counters.crc += 1,
+        }
+    });
+}
 
 #[interrupt]
 fn USART2() {
-    // SAFETY: race condition where USART2_PERIPHERAL can be accessed before being set
-    let usart2 = unsafe { USART2_PERIPHERAL.as_mut() }.unwrap();
-    let spi1 = unsafe { SPI1_PERIPHERAL.as_mut() }.unwrap();
-    let tx_buffer = unsafe { TX_BUFFER.as_mut() }.unwrap();
-    let rx_buffer = unsafe { RX_BUFFER.as_mut() }.unwrap();
-
-    // Dequeue bytes off rx buffer and transmit over USART2
-    if usart2.isr.read().txe().bit_is_set() {
-        match rx_buffer.dequeue() {
-            Some(byte) => {
-                usart2.tdr.write(|w| w.tdr().bits(byte));
-                if rx_buffer.is_empty() {
-                    usart2.cr1.modify(|_, w| w.txeie().disabled());
+    cortex_m::interrupt::free(|cs| {
+        let mut usart2 = USART2_PERIPHERAL.borrow(cs).borrow_mut();
+        let usart2 = usart2.as_mut().unwrap();
+        let mut spi1 = SPI1_PERIPHERAL.borrow(cs).borrow_mut();
+        let spi1 = spi1.as_mut().unwrap();
+        let mut tx_buffer = TX_BUFFER.borrow(cs).borrow_mut();
+        let tx_buffer = tx_buffer.as_mut().unwrap();
+        let mut rx_buffer = RX_BUFFER.borrow(cs).borrow_mut();
+        let rx_buffer = rx_buffer.as_mut().unwrap();
+
+        // Only Interrupt mode owns USART2's TX/RX data registers; in Dma mode they're driven by
+        // DMA1_CH7/DMA1_CH6, so a CPU write here (e.g. from a FE/NF-triggered entry now that
+        // errie/eie are live in both modes) would race the DMA's own writes to usart2.tdr/rdr
+        // and desync its stream. Fall straight through to the ORE bookkeeping below instead.
+        if let TransferMode::Interrupt = MODE {
+            // Dequeue bytes off rx buffer and transmit over USART2
+            if usart2.isr.read().txe().bit_is_set() {
+                match rx_buffer.dequeue() {
+                    Some(byte) => {
+                        usart2.tdr.write(|w| w.tdr().bits(byte));
+                        if rx_buffer.is_empty() {
+                            usart2.cr1.modify(|_, w| w.txeie().disabled());
+                        }
+                    }
+                    None => usart2.cr1.modify(|_, w| w.txeie().disabled()),
                 }
             }
-            None => usart2.cr1.modify(|_, w| w.txeie().disabled()),
-        }
-    }
 
-    // Read incoming bytes from USART2 and queue onto tx buffer
-    if usart2.isr.read().rxne().bit_is_set() {
-        // Read data, this clears RXNE
-        let received_byte = usart2.rdr.read().rdr().bits();
+            // Read incoming bytes from USART2. Every byte is held in the line buffer, not
+            // forwarded to tx_buffer, until we know whether it's part of an ordinary echoed
+            // line or a JSON config command; forwarding on arrival would let a command's own
+            // bytes leak onto SPI1.
+            if usart2.isr.read().rxne().bit_is_set() {
+                // Read data, this clears RXNE
+                let received_byte = usart2.rdr.read().rdr().bits();
+                let mut line = LINE_BUFFER.borrow(cs).borrow_mut();
 
-        // Queue byte, do nothing if queue is full
-        if tx_buffer.enqueue(received_byte).is_ok() {
-            // Enable TXE interrupt as buffer is now non-empty
-            spi1.cr2.modify(|_, w| w.txeie().set_bit());
-            spi1.cr1.modify(|_, w| w.spe().enabled());
+                if received_byte as u8 == b'\n' {
+                    let mut is_command = false;
+                    // Only attempt to apply a command while both queues are empty, so it can't
+                    // corrupt an in-flight frame
+                    if tx_buffer.is_empty() && rx_buffer.is_empty() {
+                        if let Ok((config, _)) = serde_json_core::de::from_slice::<Config>(&line) {
+                            apply_config(spi1, &config);
+                            if let Ok(reply) =
+                                serde_json_core::ser::to_string::<Config, LINE_LEN>(&config)
+                            {
+                                // Bail out on the first wedged byte rather than spin with
+                                // interrupts masked for the rest of the reply
+                                let mut wedged = false;
+                                for &b in reply.as_bytes() {
+                                    if !write_usart2_byte(usart2, b) {
+                                        wedged = true;
+                                        break;
+                                    }
+                                }
+                                if !wedged {
+                                    write_usart2_byte(usart2, b'\n');
+                                }
+                            }
+                            is_command = true;
+                        }
+                    }
+                    // Not a command (or couldn't be applied right now): the line was ordinary
+                    // SPI1 echo data all along, so forward it now, newline included
+                    if !is_command {
+                        for &b in line.iter() {
+                            forward_byte(spi1, tx_buffer, b as u16);
+                        }
+                        forward_byte(spi1, tx_buffer, received_byte);
+                    }
+                    line.clear();
+                } else if line.push(received_byte as u8).is_err() {
+                    // Too long to be a config command: flush it through as echo data and
+                    // restart accumulation with this byte
+                    for &b in line.iter() {
+                        forward_byte(spi1, tx_buffer, b as u16);
+                    }
+                    line.clear();
+                    let _ = line.push(received_byte as u8);
+                }
+            }
         }
-    }
-    if usart2.isr.read().ore().bit_is_set() {
-        usart2.icr.write(|w| w.orecf().set_bit());
-    }
+        if usart2.isr.read().ore().bit_is_set() {
+            usart2.icr.write(|w| w.orecf().set_bit());
+        }
+    });
 }
 
 #[interrupt]
 fn SPI1() {
-    let spi1 = unsafe { SPI1_PERIPHERAL.as_mut() }.unwrap();
-    let usart2 = unsafe { USART2_PERIPHERAL.as_mut() }.unwrap();
-    let tx_buffer = unsafe { TX_BUFFER.as_mut() }.unwrap();
-    let rx_buffer = unsafe { RX_BUFFER.as_mut() }.unwrap();
-
-    // Transmit bytes from tx buffer
-    if spi1.sr.read().txe().bit_is_set() {
-        match tx_buffer.dequeue() {
-            Some(byte) => {
-                spi1.dr.write(|w| w.dr().bits(byte));
-                while spi1.sr.read().bsy().bit_is_set() {}
-                spi1.cr1.modify(|_, w| w.spe().disabled());
-                if tx_buffer.is_empty() {
+    cortex_m::interrupt::free(|cs| {
+        let mut spi1 = SPI1_PERIPHERAL.borrow(cs).borrow_mut();
+        let spi1 = spi1.as_mut().unwrap();
+        let mut usart2 = USART2_PERIPHERAL.borrow(cs).borrow_mut();
+        let usart2 = usart2.as_mut().unwrap();
+        let mut tx_buffer = TX_BUFFER.borrow(cs).borrow_mut();
+        let tx_buffer = tx_buffer.as_mut().unwrap();
+        let mut rx_buffer = RX_BUFFER.borrow(cs).borrow_mut();
+        let rx_buffer = rx_buffer.as_mut().unwrap();
+
+        if let Err(error) = read_sr(spi1) {
+            handle_spi_error(spi1, error, tx_buffer, rx_buffer);
+            return;
+        }
+
+        // Transmit bytes from tx buffer
+        if spi1.sr.read().txe().bit_is_set() {
+            match tx_buffer.dequeue() {
+                Some(byte) => {
+                    spi1.dr.write(|w| w.dr().bits(byte));
+                    while spi1.sr.read().bsy().bit_is_set() {}
+                    spi1.cr1.modify(|_, w| w.spe().disabled());
+                    if tx_buffer.is_empty() {
+                        spi1.cr2.modify(|_, w| w.txeie().clear_bit());
+                    }
+                    if WITH_WDT {
+                        if let Some(iwdg) = IWDG_PERIPHERAL.borrow(cs).borrow().as_ref() {
+                            kick_iwdg(iwdg);
+                        }
+                    }
+                }
+                None => {
+                    spi1.cr1.modify(|_, w| w.spe().disabled());
                     spi1.cr2.modify(|_, w| w.txeie().clear_bit());
                 }
             }
-            None => {
-                spi1.cr1.modify(|_, w| w.spe().disabled());
-                spi1.cr2.modify(|_, w| w.txeie().clear_bit());
+        }
+
+        // Read incoming bytes over SPI1 and queue onto rx buffer
+        if spi1.sr.read().rxne().bit_is_set() {
+            let received_byte = spi1.dr.read().dr().bits();
+            if rx_buffer.enqueue(received_byte).is_ok() {
+                usart2.cr1.modify(|_, w| w.txeie().enabled());
             }
         }
-    }
+    });
+}
 
-    // Read incoming bytes over SPI1 and queue onto rx buffer
-    if spi1.sr.read().rxne().bit_is_set() {
-        let received_byte = spi1.dr.read().dr().bits();
-        if rx_buffer.enqueue(received_byte).is_ok() {
-            usart2.cr1.modify(|_, w| w.txeie().enabled());
+/// DMA1 channel 6 (USART2_RX, periph->mem): shuffle a completed half of `USART_RX_DMA` into
+/// `tx_buffer` so the SPI1_TX DMA channel (`DMA1_CH3`) can drain it out over SPI1.
+#[interrupt]
+fn DMA1_CH6() {
+    let dma1 = unsafe { &*stm32l4::stm32l4x2::DMA1::ptr() };
+
+    let half = if dma1.isr.read().htif6().bit_is_set() {
+        dma1.ifcr.write(|w| w.chtif6().set_bit());
+        0
+    } else {
+        dma1.ifcr.write(|w| w.ctcif6().set_bit());
+        DMA_HALF_LEN
+    };
+    let buffer = unsafe { &USART_RX_DMA[half..half + DMA_HALF_LEN] };
+
+    cortex_m::interrupt::free(|cs| {
+        let mut tx_buffer = TX_BUFFER.borrow(cs).borrow_mut();
+        let tx_buffer = tx_buffer.as_mut().unwrap();
+        for byte in buffer {
+            let _ = tx_buffer.enqueue(*byte);
         }
-    }
+    });
+}
+
+/// DMA1 channel 3 (SPI1_TX, mem->periph): once a half of `SPI_TX_DMA` has gone out to
+/// `SPI1.dr`, refill it from `tx_buffer` so the channel never runs dry.
+#[interrupt]
+fn DMA1_CH3() {
+    let dma1 = unsafe { &*stm32l4::stm32l4x2::DMA1::ptr() };
+
+    let half = if dma1.isr.read().htif3().bit_is_set() {
+        dma1.ifcr.write(|w| w.chtif3().set_bit());
+        0
+    } else {
+        dma1.ifcr.write(|w| w.ctcif3().set_bit());
+        DMA_HALF_LEN
+    };
+    let buffer = unsafe { &mut SPI_TX_DMA[half..half + DMA_HALF_LEN] };
+
+    cortex_m::interrupt::free(|cs| {
+        let mut tx_buffer = TX_BUFFER.borrow(cs).borrow_mut();
+        let tx_buffer = tx_buffer.as_mut().unwrap();
+        for slot in buffer {
+            *slot = tx_buffer.dequeue().unwrap_or(0);
+        }
+    });
+}
+
+/// DMA1 channel 2 (SPI1_RX, periph->mem): shuffle a completed half of `SPI_RX_DMA` into
+/// `rx_buffer` so the USART2_TX DMA channel (`DMA1_CH7`) can drain it out over USART2.
+#[interrupt]
+fn DMA1_CH2() {
+    let dma1 = unsafe { &*stm32l4::stm32l4x2::DMA1::ptr() };
+
+    let half = if dma1.isr.read().htif2().bit_is_set() {
+        dma1.ifcr.write(|w| w.chtif2().set_bit());
+        0
+    } else {
+        dma1.ifcr.write(|w| w.ctcif2().set_bit());
+        DMA_HALF_LEN
+    };
+    let buffer = unsafe { &SPI_RX_DMA[half..half + DMA_HALF_LEN] };
+
+    cortex_m::interrupt::free(|cs| {
+        let mut rx_buffer = RX_BUFFER.borrow(cs).borrow_mut();
+        let rx_buffer = rx_buffer.as_mut().unwrap();
+        for byte in buffer {
+            let _ = rx_buffer.enqueue(*byte);
+        }
+    });
+}
+
+/// DMA1 channel 7 (USART2_TX, mem->periph): once a half of `USART_TX_DMA` has gone out to
+/// `USART2.tdr`, refill it from `rx_buffer` so the channel never runs dry.
+#[interrupt]
+fn DMA1_CH7() {
+    let dma1 = unsafe { &*stm32l4::stm32l4x2::DMA1::ptr() };
+
+    let half = if dma1.isr.read().htif7().bit_is_set() {
+        dma1.ifcr.write(|w| w.chtif7().set_bit());
+        0
+    } else {
+        dma1.ifcr.write(|w| w.ctcif7().set_bit());
+        DMA_HALF_LEN
+    };
+    let buffer = unsafe { &mut USART_TX_DMA[half..half + DMA_HALF_LEN] };
+
+    cortex_m::interrupt::free(|cs| {
+        let mut rx_buffer = RX_BUFFER.borrow(cs).borrow_mut();
+        let rx_buffer = rx_buffer.as_mut().unwrap();
+        for slot in buffer {
+            *slot = rx_buffer.dequeue().unwrap_or(0);
+        }
+    });
 }
 
 #[entry]
@@ -147,43 +499,192 @@ fn main() -> ! {
     // USART2: Configure baud rate 9600
     dp.USART2.brr.write(|w| unsafe { w.bits(417) }); // 4Mhz / 9600 approx. 417
 
-    // SPI1: Set FIFO reception threshold to 1/4, data frame size to 8 bits, enable slave select output,
-    // enable RXNE interupt
-    dp.SPI1.cr2.write(|w| unsafe {
-        w.frxth()
-            .set_bit()
-            .ds()
-            .bits(7)
-            .ssoe()
-            .enabled()
-            .rxneie()
-            .set_bit()
-    });
+    // SPI1: Set FIFO reception threshold to 1/4, data frame size to 8 bits, enable slave select output
+    dp.SPI1.cr2.write(|w| unsafe { w.frxth().set_bit().ds().bits(7).ssoe().enabled() });
+    if let TransferMode::Interrupt = MODE {
+        // Only the interrupt path services bytes via RXNE; in Dma mode SPI1_RX DMA owns this
+        dp.SPI1.cr2.modify(|_, w| w.rxneie().set_bit());
+    }
+    // SPI1: enable the error interrupt unconditionally. read_sr()/handle_spi_error() in the
+    // SPI1() handler already run regardless of MODE, but without errie the handler is only
+    // ever entered via rxneie/txeie — which Dma mode leaves clear — so OVR/MODF/CRCERR would
+    // otherwise go undetected whenever MODE = TransferMode::Dma
+    dp.SPI1.cr2.modify(|_, w| w.errie().set_bit());
     // SPI1: set baud rate fpclk/8, SPI master
     dp.SPI1.cr1.write(|w| w.br().bits(2).mstr().set_bit());
 
-    // Enable USART, transmitter, receiver and RXNE interrupt
-    dp.USART2.cr1.write(|w| {
-        w.re()
-            .set_bit()
-            .te()
-            .set_bit()
-            .ue()
-            .set_bit()
-            .rxneie()
-            .set_bit()
+    // Enable USART, transmitter and receiver
+    dp.USART2
+        .cr1
+        .write(|w| w.re().set_bit().te().set_bit().ue().set_bit());
+    if let TransferMode::Interrupt = MODE {
+        // Only the interrupt path services bytes via RXNE; in Dma mode USART2_RX DMA owns this
+        dp.USART2.cr1.modify(|_, w| w.rxneie().set_bit());
+    }
+    // USART2: enable the error interrupt unconditionally so ORE still raises USART2() in Dma
+    // mode, where rxneie stays clear (RM0394: ORE triggers an interrupt when RXNEIE or EIE = 1)
+    dp.USART2.cr3.modify(|_, w| w.eie().set_bit());
+
+    if let TransferMode::Dma = MODE {
+        // Enable DMA1 clock
+        dp.RCC.ahb1enr.write(|w| w.dma1en().set_bit());
+
+        // DMA1 channel 6: USART2_RX (CSELR selector 3), periph->mem, into USART_RX_DMA, circular
+        dp.DMA1.cselr.modify(|_, w| unsafe { w.c6s().bits(3) });
+        dp.DMA1
+            .cpar6
+            .write(|w| unsafe { w.bits(dp.USART2.rdr.as_ptr() as u32) });
+        dp.DMA1
+            .cmar6
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(USART_RX_DMA) as u32) });
+        dp.DMA1
+            .cndtr6
+            .write(|w| unsafe { w.ndt().bits((DMA_HALF_LEN * 2) as u16) });
+        dp.DMA1.ccr6.write(|w| {
+            w.minc()
+                .set_bit()
+                .circ()
+                .set_bit()
+                .htie()
+                .enabled()
+                .tcie()
+                .enabled()
+                .en()
+                .enabled()
+        });
+
+        // DMA1 channel 3: SPI1_TX (CSELR selector 1), mem->periph, out of SPI_TX_DMA, circular
+        dp.DMA1.cselr.modify(|_, w| unsafe { w.c3s().bits(1) });
+        dp.DMA1
+            .cpar3
+            .write(|w| unsafe { w.bits(dp.SPI1.dr.as_ptr() as u32) });
+        dp.DMA1
+            .cmar3
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(SPI_TX_DMA) as u32) });
+        dp.DMA1
+            .cndtr3
+            .write(|w| unsafe { w.ndt().bits((DMA_HALF_LEN * 2) as u16) });
+        dp.DMA1.ccr3.write(|w| {
+            w.minc()
+                .set_bit()
+                .dir()
+                .set_bit()
+                .circ()
+                .set_bit()
+                .htie()
+                .enabled()
+                .tcie()
+                .enabled()
+                .en()
+                .enabled()
+        });
+
+        // DMA1 channel 2: SPI1_RX (CSELR selector 1), periph->mem, into SPI_RX_DMA, circular
+        dp.DMA1.cselr.modify(|_, w| unsafe { w.c2s().bits(1) });
+        dp.DMA1
+            .cpar2
+            .write(|w| unsafe { w.bits(dp.SPI1.dr.as_ptr() as u32) });
+        dp.DMA1
+            .cmar2
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(SPI_RX_DMA) as u32) });
+        dp.DMA1
+            .cndtr2
+            .write(|w| unsafe { w.ndt().bits((DMA_HALF_LEN * 2) as u16) });
+        dp.DMA1.ccr2.write(|w| {
+            w.minc()
+                .set_bit()
+                .circ()
+                .set_bit()
+                .htie()
+                .enabled()
+                .tcie()
+                .enabled()
+                .en()
+                .enabled()
+        });
+
+        // DMA1 channel 7: USART2_TX (CSELR selector 3), mem->periph, out of USART_TX_DMA, circular
+        dp.DMA1.cselr.modify(|_, w| unsafe { w.c7s().bits(3) });
+        dp.DMA1
+            .cpar7
+            .write(|w| unsafe { w.bits(dp.USART2.tdr.as_ptr() as u32) });
+        dp.DMA1
+            .cmar7
+            .write(|w| unsafe { w.bits(core::ptr::addr_of!(USART_TX_DMA) as u32) });
+        dp.DMA1
+            .cndtr7
+            .write(|w| unsafe { w.ndt().bits((DMA_HALF_LEN * 2) as u16) });
+        dp.DMA1.ccr7.write(|w| {
+            w.minc()
+                .set_bit()
+                .dir()
+                .set_bit()
+                .circ()
+                .set_bit()
+                .htie()
+                .enabled()
+                .tcie()
+                .enabled()
+                .en()
+                .enabled()
+        });
+
+        // SPI1: enable DMA requests on RX/TX
+        dp.SPI1
+            .cr2
+            .modify(|_, w| w.txdmaen().enabled().rxdmaen().enabled());
+        // USART2: enable DMA requests on RX/TX
+        dp.USART2
+            .cr3
+            .modify(|_, w| w.dmat().enabled().dmar().enabled());
+
+        // SPI1: enable the peripheral. In Interrupt mode forward_byte()/apply_config() toggle
+        // spe per transfer, but Dma mode never calls either, so without this SPI1 stays disabled
+        // and DMA1 just shuffles zeros through the RAM buffers forever
+        dp.SPI1.cr1.modify(|_, w| w.spe().enabled());
+
+        unsafe {
+            cortex_m::peripheral::NVIC::unmask(Interrupt::DMA1_CH6);
+            cortex_m::peripheral::NVIC::unmask(Interrupt::DMA1_CH3);
+            cortex_m::peripheral::NVIC::unmask(Interrupt::DMA1_CH2);
+            cortex_m::peripheral::NVIC::unmask(Interrupt::DMA1_CH7);
+        }
+    }
+
+    if WITH_WDT {
+        // IWDG: unlock access, select the /256 prescaler, set reload value, start it, then kick
+        // once before interrupts are unmasked so init itself doesn't eat into the first timeout
+        dp.IWDG.kr.write(|w| w.key().bits(0x5555));
+        dp.IWDG.pr.write(|w| unsafe { w.pr().bits(IWDG_PRESCALER) });
+        dp.IWDG.rlr.write(|w| w.rl().bits(IWDG_RELOAD));
+        while dp.IWDG.sr.read().pvu().bit_is_set() || dp.IWDG.sr.read().rvu().bit_is_set() {}
+        dp.IWDG.kr.write(|w| w.key().bits(0xCCCC));
+        kick_iwdg(&dp.IWDG);
+    }
+
+    cortex_m::interrupt::free(|cs| {
+        TX_BUFFER.borrow(cs).replace(Some(Queue::default()));
+        RX_BUFFER.borrow(cs).replace(Some(Queue::default()));
+        SPI1_PERIPHERAL.borrow(cs).replace(Some(dp.SPI1));
+        USART2_PERIPHERAL.borrow(cs).replace(Some(dp.USART2));
+        if WITH_WDT {
+            IWDG_PERIPHERAL.borrow(cs).replace(Some(dp.IWDG));
+        }
     });
 
+    // Unmask NVIC USART2 and SPI1 global interrupts
     unsafe {
-        TX_BUFFER = Some(Queue::default());
-        RX_BUFFER = Some(Queue::default());
-        // Unmask NVIC USART2 global interrupt
         cortex_m::peripheral::NVIC::unmask(Interrupt::SPI1);
         cortex_m::peripheral::NVIC::unmask(Interrupt::USART2);
-        SPI1_PERIPHERAL = Some(dp.SPI1);
-        USART2_PERIPHERAL = Some(dp.USART2);
     }
 
-    #[allow(clippy::empty_loop)]
-    loop {}
+    loop {
+        if WITH_WDT {
+            cortex_m::interrupt::free(|cs| {
+                if let Some(iwdg) = IWDG_PERIPHERAL.borrow(cs).borrow().as_ref() {
+                    kick_iwdg(iwdg);
+                }
+            });
+        }
+    }
 }
@@ -3,47 +3,212 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
 use cortex_m_rt::entry;
 use heapless::spsc::Queue;
 use panic_semihosting as _; // logs messages to the host stderr; requires a debugger
 use stm32u5::stm32u575::{interrupt, Interrupt, Peripherals, SPI1};
 
-static mut SPI1_PERIPHERAL: Option<SPI1> = None;
-static mut BUFFER: Option<Queue<u16, 16>> = None;
+static SPI1_PERIPHERAL: Mutex<RefCell<Option<SPI1>>> = Mutex::new(RefCell::new(None));
+static BUFFER: Mutex<RefCell<Option<Queue<u16, 16>>>> = Mutex::new(RefCell::new(None));
+
+/// Enables the IIR biquad stage between SPI1 RX and TX, following the `iir` approach used by
+/// the pounder firmware. Off by default, which preserves the raw byte echo.
+const WITH_IIR: bool = false;
+
+/// Direct-Form-I biquad: `y[n] = b0*x[n] + b1*x[n-1] + b2*x[n-2] - a1*y[n-1] - a2*y[n-2]`,
+/// with `b`/`a` already normalized by `a0`.
+#[derive(Clone, Copy)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    /// Identity filter; passes `x[n]` through unchanged, matching the non-`WITH_IIR` echo.
+    const IDENTITY: Biquad = Biquad {
+        b: [1.0, 0.0, 0.0],
+        a: [0.0, 0.0],
+        x: [0.0, 0.0],
+        y: [0.0, 0.0],
+    };
+
+    fn process(&mut self, sample: i16) -> i16 {
+        let x0 = sample as f32;
+        let y0 = self.b[0] * x0 + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x[1] = self.x[0];
+        self.x[0] = x0;
+        self.y[1] = self.y[0];
+        self.y[0] = y0;
+        y0.clamp(i16::MIN as f32, i16::MAX as f32) as i16
+    }
+}
+
+/// Biquad coefficients and history; not yet reachable over a config protocol, so edit
+/// `Biquad::IDENTITY` (or this initializer) to change them.
+static IIR: Mutex<RefCell<Biquad>> = Mutex::new(RefCell::new(Biquad::IDENTITY));
+/// Holds the low byte of a sample pair until `WITH_IIR` has both bytes of `x[n]`.
+static PENDING_LOW_BYTE: Mutex<RefCell<Option<u8>>> = Mutex::new(RefCell::new(None));
+
+/// Enables SPI1 hardware CRC checking (`spi_cr1().crcen`/`spi_crcpoly()`). Off by default since
+/// it changes what is shifted out on the last byte of a frame. Unlike the classic SPI on the
+/// controller side, this SPI has no `CRCNEXT` bit: the CRC engine computes/checks automatically
+/// at each `spi_cr2().tsize()` frame boundary, so it only has a defined "last byte" to act on
+/// when `WITH_NSS_FRAMING` also gives it one (see the assertion below).
+const WITH_CRC: bool = false;
+/// CCITT polynomial (x^16 + x^12 + x^5 + 1) programmed into `SPI1.spi_crcpoly()` when `WITH_CRC` is set.
+const CRC_POLYNOMIAL: u16 = 0x1021;
+const _: () = assert!(
+    !WITH_CRC || WITH_NSS_FRAMING,
+    "WITH_CRC needs a real frame boundary: enable WITH_NSS_FRAMING too"
+);
+
+/// SPI1 fault conditions distinguished from a successful transfer.
+#[derive(Clone, Copy)]
+enum Error {
+    Overrun,
+    ModeFault,
+    Crc,
+}
+
+/// Counts of each `Error` kind observed, for a debugger/semihosting log to inspect.
+#[derive(Default, Clone, Copy)]
+struct ErrorCounters {
+    overrun: u32,
+    mode_fault: u32,
+    crc: u32,
+    /// TXP underrun events: the master clocked out a byte before we staged a reply, so the
+    /// `spi_udrdr` sentinel went out instead.
+    underrun: u32,
+}
+
+static ERROR_COUNTERS: Mutex<RefCell<ErrorCounters>> = Mutex::new(RefCell::new(ErrorCounters {
+    overrun: 0,
+    mode_fault: 0,
+    crc: 0,
+    underrun: 0,
+}));
+
+/// Delimit echo frames on NSS assertion/deassertion (`spi_cr2().tsize()`) instead of racing the
+/// master's clock: lets the slave resynchronize cleanly after an underrun.
+const WITH_NSS_FRAMING: bool = false;
+
+/// Read SPI1.spi_sr() and classify a fault, modeled on embassy's `read_sr`.
+fn read_sr(spi1: &SPI1) -> Result<(), Error> {
+    let sr = spi1.spi_sr().read();
+    if sr.ovr().bit_is_set() {
+        Err(Error::Overrun)
+    } else if sr.modf().bit_is_set() {
+        Err(Error::ModeFault)
+    } else if sr.crce().bit_is_set() {
+        Err(Error::Crc)
+    } else {
+        Ok(())
+    }
+}
+
+/// Clear the condition reported by `read_sr`, flush `buffer` to a known empty state, and record
+/// the error in `ERROR_COUNTERS`.
+fn handle_spi_error(spi1: &SPI1, error: Error, buffer: &mut Queue<u16, 16>) {
+    match error {
+        Error::Overrun => spi1.spi_ifcr().write(|w| w.ovrc().set_bit()),
+        Error::ModeFault => spi1.spi_ifcr().write(|w| w.modfc().set_bit()),
+        Error::Crc => spi1.spi_ifcr().write(|w| w.crcec().set_bit()),
+    }
+
+    while buffer.dequeue().is_some() {}
+
+    cortex_m::interrupt::free(|cs| {
+        // Drop a stale low byte too, or it would pair with the first post-recovery byte and
+        // produce one garbage biquad input/output pair right after every recovery
+        PENDING_LOW_BYTE.borrow(cs).replace(None);
+
+        let mut counters = ERROR_COUNTERS.borrow(cs).borrow_mut();
+        match error {
+            Error::Overrun => counters.overrun += 1,
+            Error::ModeFault => counters.mode_fault += 1,
+            Error::Crc => counters.crc += 1,
+        }
+    });
+}
 
 #[interrupt]
 fn SPI1() {
-    let spi1 = unsafe { SPI1_PERIPHERAL.as_mut() }.unwrap();
-    let buffer = unsafe { BUFFER.as_mut() }.unwrap();
+    cortex_m::interrupt::free(|cs| {
+        let mut spi1 = SPI1_PERIPHERAL.borrow(cs).borrow_mut();
+        let spi1 = spi1.as_mut().unwrap();
+        let mut buffer = BUFFER.borrow(cs).borrow_mut();
+        let buffer = buffer.as_mut().unwrap();
+
+        if let Err(error) = read_sr(spi1) {
+            handle_spi_error(spi1, error, buffer);
+            return;
+        }
 
-    if spi1.spi_sr().read().rxp().bit_is_set() {
-        let received_byte = spi1.spi_rxdr().read().rxdr().bits() as u16;
+        if spi1.spi_sr().read().rxp().bit_is_set() {
+            let received_byte = spi1.spi_rxdr().read().rxdr().bits() as u8;
 
-        if buffer.enqueue(received_byte).is_ok() {
-            spi1.spi_ier().modify(|_, w| w.txpie().set_bit());
+            if WITH_IIR {
+                let mut pending = PENDING_LOW_BYTE.borrow(cs).borrow_mut();
+                match *pending {
+                    None => *pending = Some(received_byte),
+                    Some(low) => {
+                        *pending = None;
+                        let sample = i16::from_le_bytes([low, received_byte]);
+                        let filtered = IIR.borrow(cs).borrow_mut().process(sample);
+                        let [low, high] = filtered.to_le_bytes();
+                        // Enqueue both bytes of the filtered sample together, or neither: a
+                        // lone `low` enqueued without its `high` would permanently skew every
+                        // later pair's byte alignment for the rest of the session
+                        if buffer.len() + 2 <= buffer.capacity() {
+                            let _ = buffer.enqueue(low as u16);
+                            let _ = buffer.enqueue(high as u16);
+                            spi1.spi_ier().modify(|_, w| w.txpie().set_bit());
+                        }
+                    }
+                }
+            } else if buffer.enqueue(received_byte as u16).is_ok() {
+                spi1.spi_ier().modify(|_, w| w.txpie().set_bit());
+            }
         }
-    }
 
-    // No synchronization. I assume the reason this works is because the SPI clock rate is just right
-    // such that the slave doesn't write too fast to cause an underrun.
-    if spi1.spi_sr().read().txp().bit_is_set() {
-        match buffer.dequeue() {
-            Some(byte) => {
-                spi1.txdr8().write(|w| unsafe { w.txdr().bits(byte as u8) });
-                if buffer.is_empty() {
+        // Prime the TX FIFO watermark: keep staging bytes while TXP is set so a reply is already
+        // queued before the master's next clock edge, instead of refilling one byte at a time.
+        while spi1.spi_sr().read().txp().bit_is_set() {
+            match buffer.dequeue() {
+                Some(byte) => spi1.txdr8().write(|w| unsafe { w.txdr().bits(byte as u8) }),
+                None => {
                     spi1.spi_ier().modify(|_, w| w.txpie().clear_bit());
+                    break;
                 }
             }
-            None => {
-                spi1.spi_ier().modify(|_, w| w.txpie().clear_bit());
-            }
         }
-    }
+        if buffer.is_empty() {
+            spi1.spi_ier().modify(|_, w| w.txpie().clear_bit());
+        }
 
-    // Reset underrun error
-    if spi1.spi_sr().read().udr().bit_is_set() {
-        spi1.spi_ifcr().write(|w| w.udrc().set_bit());
-    }
+        // Track underrun events: the dummy byte in spi_udrdr went out instead of a real reply.
+        if spi1.spi_sr().read().udr().bit_is_set() {
+            spi1.spi_ifcr().write(|w| w.udrc().set_bit());
+            ERROR_COUNTERS.borrow(cs).borrow_mut().underrun += 1;
+            // A dummy byte went out in place of a real reply, so whatever low byte is
+            // still pending belongs to a sample that just got skewed; drop it the same
+            // way handle_spi_error does for OVR/MODF/CRC.
+            PENDING_LOW_BYTE.borrow(cs).replace(None);
+        }
+
+        // With NSS framing, EOT (NSS deasserted) marks a clean frame boundary: flush whatever
+        // is left of the stale frame instead of letting the echo stream stay skewed.
+        if WITH_NSS_FRAMING && spi1.spi_sr().read().eot().bit_is_set() {
+            spi1.spi_ifcr().write(|w| w.eotc().set_bit().txtfc().set_bit());
+            while buffer.dequeue().is_some() {}
+        }
+    });
 }
 
 #[entry]
@@ -96,16 +261,35 @@ fn main() -> ! {
         .write(|w| unsafe { w.udrdr().bits(b'?' as u32) });
     // Enable receive packet interrupt
     dp.SPI1.spi_ier().write(|w| w.rxpie().set_bit());
-    // Enable SPI as slave
-    dp.SPI1.spi_cr1().write(|w| w.spe().set_bit());
+
+    if WITH_NSS_FRAMING {
+        // SPI1: one data frame per NSS assertion, with an EOT interrupt on deassertion
+        dp.SPI1.spi_cr2().write(|w| unsafe { w.tsize().bits(1) });
+        dp.SPI1.spi_ier().modify(|_, w| w.eotie().set_bit());
+    }
+
+    if WITH_CRC {
+        // SPI1: program the CRC polynomial before enabling the peripheral
+        dp.SPI1
+            .spi_crcpoly()
+            .write(|w| unsafe { w.crcpoly().bits(CRC_POLYNOMIAL as u32) });
+    }
+
+    // Enable SPI as slave, with CRC checking if configured above
+    dp.SPI1
+        .spi_cr1()
+        .write(|w| w.spe().set_bit().crcen().bit(WITH_CRC));
     // Load TX FIFO with initial byte '!'
     dp.SPI1.txdr8().write(|w| unsafe { w.txdr().bits(b'!') });
 
+    cortex_m::interrupt::free(|cs| {
+        BUFFER.borrow(cs).replace(Some(Queue::default()));
+        SPI1_PERIPHERAL.borrow(cs).replace(Some(dp.SPI1));
+    });
+
+    // Unmask global interrupts
     unsafe {
-        BUFFER = Some(Queue::default());
-        // Unmask global interrupts
         cortex_m::peripheral::NVIC::unmask(Interrupt::SPI1);
-        SPI1_PERIPHERAL = Some(dp.SPI1);
     }
 
     #[allow(clippy::empty_loop)]